@@ -1,9 +1,18 @@
-use clap::{command, Parser};
-use csv::{Reader, Writer};
+use clap::{Parser, ValueEnum};
+use csv::{Reader, StringRecord, Writer};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use num::{Float, NumCast};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use rayon::prelude::*;
+use std::collections::HashSet;
 use std::fs::File;
+use std::io::{Read, Write};
 use std::iter::Iterator;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 #[derive(Parser, Debug)]
 #[command(
@@ -20,106 +29,512 @@ struct Args {
     /// Delimiter of the input files
     #[arg(short, long, default_value_t = ',')]
     delimiter: char,
+    /// Number of threads to use (0 lets rayon pick one per logical core)
+    #[arg(short, long, default_value_t = 0)]
+    threads: usize,
+    /// Effect-size statistic to report
+    #[arg(short, long, value_enum, default_value_t = Statistic::Cohen)]
+    statistic: Statistic,
+    /// Comma-separated tokens to treat as missing values
+    #[arg(long, value_delimiter = ',', default_value = "NA,NaN,nan,NULL,null")]
+    na_values: Vec<String>,
+    /// What to do when a missing value is encountered
+    #[arg(long, value_enum, default_value_t = NaPolicy::Skip)]
+    na_policy: NaPolicy,
+    /// Force gzip (de)compression regardless of the file extensions
+    #[arg(short, long)]
+    gzip: bool,
+    /// Number of label permutations used to estimate a p-value (0 disables it)
+    #[arg(short, long, default_value_t = 0)]
+    permutations: usize,
+    /// Seed for the permutation RNG, so runs are reproducible
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+    /// Emit lower/upper confidence bounds for each effect size
+    #[arg(long)]
+    ci: bool,
+    /// Confidence level for the interval emitted by --ci
+    #[arg(long, default_value_t = 0.95)]
+    ci_level: f64,
+    /// Header name of the id column (defaults to the first column)
+    #[arg(long)]
+    id_column: Option<String>,
+    /// Only use these sample columns (by header name); defaults to all
+    #[arg(long, value_delimiter = ',')]
+    select: Vec<String>,
+    /// Exclude these sample columns (by header name)
+    #[arg(long, value_delimiter = ',')]
+    exclude: Vec<String>,
+}
+
+/// Effect-size statistics that the tool knows how to emit.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+enum Statistic {
+    /// Cohen's d.
+    Cohen,
+    /// Hedges' g, i.e. Cohen's d with the small-sample bias correction.
+    Hedges,
+}
+
+impl Statistic {
+    /// Header used for the effect-size column in the output file.
+    fn column_name(&self) -> &'static str {
+        match self {
+            Statistic::Cohen => "cohen_d",
+            Statistic::Hedges => "hedges_g",
+        }
+    }
+}
+
+/// How to react when a cell holds a recognized missing-value token.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+enum NaPolicy {
+    /// Drop the missing value from the sample before computing the statistic.
+    Skip,
+    /// Abort the run, as the tool did historically.
+    Error,
+}
+
+/// Knobs controlling how a pair of matrices is turned into effect sizes.
+///
+/// These are split out of [`Args`] so the computation can be driven from tests
+/// without reaching for the filesystem.
+struct AnalysisOptions {
+    statistic: Statistic,
+    na_values: HashSet<String>,
+    na_policy: NaPolicy,
+    permutations: usize,
+    seed: u64,
+    ci: bool,
+    ci_level: f64,
+    id_column: Option<String>,
+    select: Vec<String>,
+    exclude: Vec<String>,
+}
+
+impl Default for AnalysisOptions {
+    fn default() -> Self {
+        AnalysisOptions {
+            statistic: Statistic::Cohen,
+            na_values: HashSet::new(),
+            na_policy: NaPolicy::Skip,
+            permutations: 0,
+            seed: 0,
+            ci: false,
+            ci_level: 0.95,
+            id_column: None,
+            select: Vec::new(),
+            exclude: Vec::new(),
+        }
+    }
 }
 
 fn main() {
     // Parse the command-line arguments.
     let args: Args = Args::parse();
 
+    // Size the rayon thread-pool before any parallel work happens. A value of
+    // zero leaves rayon to its default of one thread per logical core.
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(args.threads)
+        .build_global()
+        .expect("Failed to configure the thread pool");
+
     println!("Building case reader...");
-    let mut case_samples: Reader<File> = csv::ReaderBuilder::new()
-        .delimiter(args.delimiter as u8)
-        .from_path(&args.case_expression_matrix)
-        .expect("Failed to read input case expression matrix");
+    let mut case_samples = build_reader(
+        &args.case_expression_matrix,
+        args.delimiter,
+        args.gzip,
+        "case expression matrix",
+    );
 
     println!("Building control reader...");
-    let mut control_samples: Reader<File> = csv::ReaderBuilder::new()
-        .delimiter(args.delimiter as u8)
-        .from_path(&args.control_expression_matrix)
-        .expect("Failed to read input case expression matrix");
+    let mut control_samples = build_reader(
+        &args.control_expression_matrix,
+        args.delimiter,
+        args.gzip,
+        "control expression matrix",
+    );
+
+    let mut writer = build_writer(&args.output_path, args.gzip);
+
+    let options = AnalysisOptions {
+        statistic: args.statistic,
+        na_values: args.na_values.into_iter().collect(),
+        na_policy: args.na_policy,
+        permutations: args.permutations,
+        seed: args.seed,
+        ci: args.ci,
+        ci_level: args.ci_level,
+        id_column: args.id_column,
+        select: args.select,
+        exclude: args.exclude,
+    };
 
-    let mut writer =
-        Writer::from_path(&args.output_path).expect("Could not open output file for writing.");
+    process_csvs(&mut case_samples, &mut control_samples, &mut writer, &options);
+}
 
-    process_csvs(&mut case_samples, &mut control_samples, &mut writer);
+/// True when `path` looks gzip-compressed, or when the user forced it.
+fn is_gzip(path: &Path, force: bool) -> bool {
+    force
+        || path
+            .extension()
+            .map(|ext| ext.eq_ignore_ascii_case("gz"))
+            .unwrap_or(false)
+}
+
+/// Open a csv reader over `path`, transparently inflating gzip inputs.
+fn build_reader(path: &Path, delimiter: char, force_gzip: bool, what: &str) -> Reader<Box<dyn Read>> {
+    let file = File::open(path).unwrap_or_else(|_| panic!("Failed to read input {what}"));
+    let source: Box<dyn Read> = if is_gzip(path, force_gzip) {
+        Box::new(GzDecoder::new(file))
+    } else {
+        Box::new(file)
+    };
+    csv::ReaderBuilder::new()
+        .delimiter(delimiter as u8)
+        .from_reader(source)
+}
+
+/// Open a csv writer over `path`, gzip-compressing the output when requested.
+fn build_writer(path: &Path, force_gzip: bool) -> Writer<Box<dyn Write>> {
+    let file = File::create(path).expect("Could not open output file for writing.");
+    let sink: Box<dyn Write> = if is_gzip(path, force_gzip) {
+        Box::new(GzEncoder::new(file, Compression::default()))
+    } else {
+        Box::new(file)
+    };
+    Writer::from_writer(sink)
 }
 
 fn process_csvs<R, T, V>(
     case_samples: &mut Reader<R>,
     control_samples: &mut Reader<T>,
     writer: &mut Writer<V>,
+    options: &AnalysisOptions,
 ) where
     V: std::io::Write,
-    R: std::io::Read + std::io::Seek,
-    T: std::io::Read + std::io::Seek,
+    R: std::io::Read,
+    T: std::io::Read,
 {
-    let control_start_position = control_samples.position().clone();
-    let case_start_position = case_samples.position().clone();
-    // Sort the control and case samples to have the same row names
-    // I assume that the first row is made up of column names
-    let control_row_names: Vec<String> = control_samples
-        .records()
-        .map(|x| x.unwrap().get(0).unwrap().to_owned())
-        .collect();
-    let case_row_names: Vec<String> = case_samples
+    // Resolve the id column and the sample columns to use from each header,
+    // cloning them since the records iterator needs the reader afterwards.
+    let case_header = case_samples.headers().expect("Missing case header").clone();
+    let control_header = control_samples
+        .headers()
+        .expect("Missing control header")
+        .clone();
+    let case_id = id_index(&case_header, options, "case");
+    let control_id = id_index(&control_header, options, "control");
+    let case_cols = sample_indices(&case_header, case_id, options);
+    let control_cols = sample_indices(&control_header, control_id, options);
+
+    // `--select`/`--exclude` are matched against each file's own header, so a
+    // selection that only names one side's samples can leave the other matrix
+    // with too few columns to compute a variance. Fail loudly here rather than
+    // quietly emitting NaN for every gene.
+    check_sample_count(case_cols.len(), "case");
+    check_sample_count(control_cols.len(), "control");
+
+    // Pull both matrices into memory, keyed by id, so case and control files
+    // that are ordered differently or only partially overlap still line up.
+    let control_map: std::collections::HashMap<String, Vec<f64>> = control_samples
         .records()
-        .map(|x| x.unwrap().get(0).unwrap().to_owned())
+        .map(|x| {
+            let record = x.expect("Couldn't read control record");
+            let id = record.get(control_id).unwrap().to_owned();
+            (id, read_values(&record, &control_cols, options, "control"))
+        })
         .collect();
 
-    case_samples.seek(case_start_position).unwrap();
-    control_samples.seek(control_start_position).unwrap();
+    // Iterate case rows in file order, dropping ids absent from the control
+    // matrix. This replaces the old positional zip + row-name equality check.
+    let mut aligned: Vec<(String, Vec<f64>, Vec<f64>)> = Vec::new();
+    for record in case_samples.records() {
+        let record = record.expect("Couldn't read case record");
+        let id = record.get(case_id).unwrap().to_owned();
+        if let Some(control_values) = control_map.get(&id) {
+            let case_values = read_values(&record, &case_cols, options, "case");
+            aligned.push((id, case_values, control_values.clone()));
+        }
+    }
+    let row_names: Vec<String> = aligned.iter().map(|(id, _, _)| id.clone()).collect();
 
-    // I clone since I have to re-borrow this later...
-    let row_names_match = control_row_names
-        .clone()
-        .into_iter()
-        .zip(case_row_names)
-        .all(|(x, y)| x == y);
+    println!("Computing cohen's d...");
+    // rayon's indexed iterator keeps the collected results in stable row order.
+    // The enumerate index also seeds each row's RNG, so permutation p-values
+    // are reproducible no matter how the work is scheduled across threads.
+    // The z multiplier for the requested confidence level is the same for every
+    // row, so compute it once up front.
+    let z = z_score(options.ci_level);
+    let result: Vec<RowOutput> = aligned
+        .par_iter()
+        .enumerate()
+        .map(|(row, (_, case_values, control_values))| {
+            // Variance needs at least two observations per group, so a row that
+            // has been whittled below that by missing values has no defined
+            // effect size: report NaN rather than dividing by zero.
+            let n_case = case_values.len();
+            let n_control = control_values.len();
+            if n_case < 2 || n_control < 2 {
+                return RowOutput {
+                    effect: f64::NAN,
+                    p_value: (options.permutations > 0).then_some(f64::NAN),
+                    ci: options.ci.then_some((f64::NAN, f64::NAN)),
+                };
+            }
+
+            let d = cohen(case_values.clone(), control_values.clone());
+            let effect = match options.statistic {
+                Statistic::Cohen => d,
+                // Hedges' g is Cohen's d scaled by the bias-correction factor
+                // J = 1 - 3 / (4 * df - 1), with df = n_case + n_control - 2.
+                Statistic::Hedges => {
+                    let df = (n_case + n_control - 2) as f64;
+                    let j = 1.0 - 3.0 / (4.0 * df - 1.0);
+                    d * j
+                }
+            };
+
+            let p_value = (options.permutations > 0).then(|| {
+                permutation_pvalue(case_values, control_values, d, options.permutations, row, options.seed)
+            });
+
+            let ci = options.ci.then(|| confidence_interval(effect, n_case, n_control, z));
+
+            RowOutput { effect, p_value, ci }
+        })
+        .collect();
 
-    if !row_names_match {
-        println!("ERROR: Row names between case and control files do not match up");
-        return;
+    // Benjamini-Hochberg adjustment needs every row's p-value at once.
+    let fdr: Vec<f64> = if options.permutations > 0 {
+        let p_values: Vec<f64> = result.iter().map(|r| r.p_value.unwrap()).collect();
+        benjamini_hochberg(&p_values)
+    } else {
+        Vec::new()
     };
 
-    println!("Computing cohen's d...");
-    let result: Vec<f64> = case_samples
-        .records()
-        .zip(control_samples.records())
-        .skip(1)
-        .map(|(case, control)| {
-            let case_values: Vec<f64> = case
-                .expect("Couldn't read case record")
-                .into_iter()
-                .skip(1)
-                .map(|x| {
-                    x.parse()
-                        .unwrap_or_else(|_| panic!("non-float value in case record: {x}"))
-                })
-                .collect();
-
-            let control_values: Vec<f64> = control
-                .expect("Couldn't read case record")
-                .into_iter()
-                .skip(1)
-                .map(|x| {
-                    x.parse()
-                        .unwrap_or_else(|_| panic!("non-float value in case record: {x}"))
-                })
-                .collect();
+    let mut header = vec!["row_names", options.statistic.column_name()];
+    if options.permutations > 0 {
+        header.push("p_value");
+        header.push("fdr");
+    }
+    if options.ci {
+        header.push("ci_lower");
+        header.push("ci_upper");
+    }
+    writer.write_record(&header).unwrap();
+
+    for (row, (row_name, output)) in row_names.into_iter().zip(result).enumerate() {
+        let mut record = vec![row_name, format!("{}", output.effect)];
+        if options.permutations > 0 {
+            record.push(format!("{}", output.p_value.unwrap()));
+            record.push(format!("{}", fdr[row]));
+        }
+        if let Some((lower, upper)) = output.ci {
+            record.push(format!("{}", lower));
+            record.push(format!("{}", upper));
+        }
+        writer.write_record(record).unwrap();
+    }
+    writer.flush().unwrap();
 
-            cohen(case_values, control_values)
+    println!("Done!");
+}
+
+/// Everything computed for a single gene row.
+struct RowOutput {
+    effect: f64,
+    p_value: Option<f64>,
+    ci: Option<(f64, f64)>,
+}
+
+/// Estimate a two-sided permutation p-value for a single row.
+///
+/// The case and control values are pooled and repeatedly reshuffled into
+/// pseudo groups of the original sizes; the p-value is the fraction of
+/// permutations whose |Cohen's d| reaches the observed |d|.
+fn permutation_pvalue(
+    case: &[f64],
+    control: &[f64],
+    observed: f64,
+    permutations: usize,
+    row: usize,
+    seed: u64,
+) -> f64 {
+    let n_case = case.len();
+    let mut pooled: Vec<f64> = case.iter().chain(control.iter()).copied().collect();
+    let observed = observed.abs();
+    let mut rng = StdRng::seed_from_u64(seed.wrapping_add(row as u64));
+
+    let extreme = (0..permutations)
+        .filter(|_| {
+            pooled.shuffle(&mut rng);
+            let (pseudo_case, pseudo_control) = pooled.split_at(n_case);
+            cohen(pseudo_case.to_vec(), pseudo_control.to_vec()).abs() >= observed
         })
+        .count();
+
+    extreme as f64 / permutations as f64
+}
+
+/// Large-sample confidence interval for an effect size.
+///
+/// Uses the usual approximation var(d) = (n_case + n_control) / (n_case *
+/// n_control) + d^2 / (2 * (n_case + n_control)) and reports d +/- z * SE.
+fn confidence_interval(d: f64, n_case: usize, n_control: usize, z: f64) -> (f64, f64) {
+    let n_case = n_case as f64;
+    let n_control = n_control as f64;
+    let total = n_case + n_control;
+    let variance = total / (n_case * n_control) + d * d / (2.0 * total);
+    let se = variance.sqrt();
+    (d - z * se, d + z * se)
+}
+
+/// Two-sided normal quantile for a confidence `level` (e.g. 0.95 -> ~1.96).
+///
+/// Implements Acklam's rational approximation to the inverse standard normal
+/// CDF, which is accurate to well within the precision we report.
+fn z_score(level: f64) -> f64 {
+    // The two-sided interval puts (1 - level) / 2 in each tail.
+    let p = 1.0 - (1.0 - level) / 2.0;
+
+    const A: [f64; 6] = [
+        -3.969683028665376e+01,
+        2.209460984245205e+02,
+        -2.759285104469687e+02,
+        1.38357751867269e+02,
+        -3.066479806614716e+01,
+        2.506628277459239e+00,
+    ];
+    const B: [f64; 5] = [
+        -5.447609879822406e+01,
+        1.615858368580409e+02,
+        -1.556989798598866e+02,
+        6.680131188771972e+01,
+        -1.328068155288572e+01,
+    ];
+    const C: [f64; 6] = [
+        -7.784894002430293e-03,
+        -3.223964580411365e-01,
+        -2.400758277161838e+00,
+        -2.549732539343734e+00,
+        4.374664141464968e+00,
+        2.938163982698783e+00,
+    ];
+    const D: [f64; 4] = [
+        7.784695709041462e-03,
+        3.224671290700398e-01,
+        2.445134137142996e+00,
+        3.754408661907416e+00,
+    ];
+    const P_LOW: f64 = 0.02425;
+    const P_HIGH: f64 = 1.0 - P_LOW;
+
+    if p < P_LOW {
+        let q = (-2.0 * p.ln()).sqrt();
+        (((((C[0] * q + C[1]) * q + C[2]) * q + C[3]) * q + C[4]) * q + C[5])
+            / ((((D[0] * q + D[1]) * q + D[2]) * q + D[3]) * q + 1.0)
+    } else if p <= P_HIGH {
+        let q = p - 0.5;
+        let r = q * q;
+        (((((A[0] * r + A[1]) * r + A[2]) * r + A[3]) * r + A[4]) * r + A[5]) * q
+            / (((((B[0] * r + B[1]) * r + B[2]) * r + B[3]) * r + B[4]) * r + 1.0)
+    } else {
+        let q = (-2.0 * (1.0 - p).ln()).sqrt();
+        -(((((C[0] * q + C[1]) * q + C[2]) * q + C[3]) * q + C[4]) * q + C[5])
+            / ((((D[0] * q + D[1]) * q + D[2]) * q + D[3]) * q + 1.0)
+    }
+}
+
+/// Benjamini-Hochberg adjusted p-values, preserving input order.
+///
+/// Non-finite entries (rows without a defined p-value) are left untouched and
+/// excluded from the number of tests `m`.
+fn benjamini_hochberg(p_values: &[f64]) -> Vec<f64> {
+    let mut order: Vec<usize> = (0..p_values.len())
+        .filter(|&i| p_values[i].is_finite())
         .collect();
+    order.sort_by(|&a, &b| p_values[a].partial_cmp(&p_values[b]).unwrap());
+
+    let m = order.len() as f64;
+    let mut adjusted = vec![f64::NAN; p_values.len()];
+    let mut running_min = 1.0;
+    // Walk from the largest rank downward, taking the running minimum so the
+    // adjusted values stay monotonic in the original p-values.
+    for rank in (1..=order.len()).rev() {
+        let idx = order[rank - 1];
+        let value = (p_values[idx] * m / rank as f64).min(1.0);
+        running_min = running_min.min(value);
+        adjusted[idx] = running_min;
+    }
+    adjusted
+}
 
-    writer.write_record(vec!["row_names", "cohen_d"]).unwrap();
-    for (row_name, value) in control_row_names.into_iter().zip(result.into_iter()) {
-        writer
-            .write_record(vec![row_name, format!("{}", value)])
-            .unwrap();
+/// Locate the id column in a header, falling back to the first column.
+fn id_index(header: &StringRecord, options: &AnalysisOptions, kind: &str) -> usize {
+    match &options.id_column {
+        Some(name) => header
+            .iter()
+            .position(|h| h == name)
+            .unwrap_or_else(|| panic!("id column {name:?} not found in {kind} header")),
+        None => 0,
     }
-    writer.flush().unwrap();
+}
 
-    println!("Done!");
+/// Resolve which sample columns to read, honouring `--select`/`--exclude`.
+///
+/// The id column is always dropped; `--select` (when given) restricts to the
+/// named columns and `--exclude` removes them, both matched by header name.
+fn sample_indices(header: &StringRecord, id: usize, options: &AnalysisOptions) -> Vec<usize> {
+    header
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| *i != id)
+        .filter(|(_, name)| options.select.is_empty() || options.select.iter().any(|s| s == name))
+        .filter(|(_, name)| !options.exclude.iter().any(|e| e == name))
+        .map(|(i, _)| i)
+        .collect()
+}
+
+/// Abort when fewer than two sample columns survive `--select`/`--exclude`.
+fn check_sample_count(n: usize, kind: &str) {
+    if n < 2 {
+        panic!(
+            "{kind} matrix has {n} sample column(s) after --select/--exclude; \
+             need at least 2 to compute an effect size"
+        );
+    }
+}
+
+/// Read the selected numeric cells of a record, honouring the missing policy.
+///
+/// Cells whose text matches one of the recognized missing tokens are either
+/// dropped or, under [`NaPolicy::Error`], abort the run; anything else is
+/// parsed as a float.
+fn read_values(
+    record: &StringRecord,
+    indices: &[usize],
+    options: &AnalysisOptions,
+    kind: &str,
+) -> Vec<f64> {
+    indices
+        .iter()
+        .filter_map(|&i| {
+            let x = record.get(i).unwrap();
+            if options.na_values.contains(x) {
+                match options.na_policy {
+                    NaPolicy::Skip => None,
+                    NaPolicy::Error => panic!("missing value in {kind} record: {x:?}"),
+                }
+            } else {
+                Some(
+                    x.parse()
+                        .unwrap_or_else(|_| panic!("non-float value in {kind} record: {x}")),
+                )
+            }
+        })
+        .collect()
 }
 
 /// Calculate the mean of the values in the input vector
@@ -137,11 +552,11 @@ where
 }
 
 /// Calculate the variance of the values in the input vector
-fn var<F>(data: &Vec<F>) -> F
+fn var<F>(data: &[F]) -> F
 where
     F: Float + std::iter::Sum,
 {
-    let data_mean = mean(data.clone()).unwrap();
+    let data_mean = mean(data.to_vec()).unwrap();
     let count = &data.len();
 
     let variance = data
@@ -205,10 +620,10 @@ mod tests {
 
     #[test]
     fn variance_of_values() {
-        assert_eq!(var(&vec![1., 2., 3.]), 1.);
-        assert_eq!(var(&vec![10., 10., 10.]), 0.);
-        assert_eq!(var(&vec![0., 12., 0., 23.]), 122.25);
-        assert_eq!(var(&vec![1., 2., 1.]), 0.3333333333333333);
+        assert_eq!(var(&[1., 2., 3.]), 1.);
+        assert_eq!(var(&[10., 10., 10.]), 0.);
+        assert_eq!(var(&[0., 12., 0., 23.]), 122.25);
+        assert_eq!(var(&[1., 2., 1.]), 0.3333333333333333);
     }
 
     #[test]
@@ -239,7 +654,12 @@ gene_3,12.3,12.6,11.1
             ReaderBuilder::new().from_reader(Cursor::new(controls.as_bytes()));
         let mut writer = WriterBuilder::new().from_writer(vec![]);
 
-        process_csvs(&mut case_samples, &mut control_samples, &mut writer);
+        process_csvs(
+            &mut case_samples,
+            &mut control_samples,
+            &mut writer,
+            &AnalysisOptions::default(),
+        );
 
         let output_data = String::from_utf8(writer.into_inner().unwrap()).unwrap();
         assert_eq!(
@@ -272,7 +692,12 @@ gene_3,9.6425133676228,11.0997575073032,9.17697194351323,8.82739260182
             ReaderBuilder::new().from_reader(Cursor::new(controls.as_bytes()));
         let mut writer = WriterBuilder::new().from_writer(vec![]);
 
-        process_csvs(&mut case_samples, &mut control_samples, &mut writer);
+        process_csvs(
+            &mut case_samples,
+            &mut control_samples,
+            &mut writer,
+            &AnalysisOptions::default(),
+        );
 
         let output_data = String::from_utf8(writer.into_inner().unwrap()).unwrap();
         assert_eq!(
@@ -282,6 +707,265 @@ row_names,cohen_d
 gene_1,-2.6462737190827195
 gene_2,1.7993829062243292
 gene_3,-10.169673185162345
+"
+        )
+    }
+
+    #[test]
+    fn itegration_hedges() {
+        let cases = "\
+row_names,sample1,sample2,sample3
+gene_1,2.2,1.3,3.1
+gene_2,1.3,2.2,3.1
+gene_3,3.1,2.2,1.3
+";
+        let controls = "\
+row_names,sample4,sample5,sample6
+gene_1,12.6,11.1,12.3
+gene_2,11.1,12.3,12.6
+gene_3,12.3,12.6,11.1
+";
+        let mut case_samples = ReaderBuilder::new().from_reader(Cursor::new(cases.as_bytes()));
+        let mut control_samples =
+            ReaderBuilder::new().from_reader(Cursor::new(controls.as_bytes()));
+        let mut writer = WriterBuilder::new().from_writer(vec![]);
+
+        process_csvs(
+            &mut case_samples,
+            &mut control_samples,
+            &mut writer,
+            &AnalysisOptions {
+                statistic: Statistic::Hedges,
+                ..Default::default()
+            },
+        );
+
+        // df = 3 + 3 - 2 = 4, so J = 1 - 3 / 15 = 0.8 scales each d.
+        let output_data = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+        assert_eq!(
+            output_data,
+            "\
+row_names,hedges_g
+gene_1,-9.239528607504221
+gene_2,-9.239528607504221
+gene_3,-9.239528607504221
+"
+        )
+    }
+
+    #[test]
+    fn itegration_join_reordered() {
+        // The control rows are in a different order and carry an extra gene;
+        // the hash-map join still lines everything up by id in case order.
+        let cases = "\
+row_names,sample1,sample2,sample3
+gene_1,-0.535058383960151,1.36337028207967,1.94555121778008
+gene_2,-0.00508937611274737,2.05525510002174,-0.605214688134933
+gene_3,-0.0224148926648615,1.00937541977038,-0.675006944468402
+";
+        let controls = "\
+row_names,sample4,sample5,sample6,sample7
+gene_3,9.6425133676228,11.0997575073032,9.17697194351323,8.82739260182
+gene_999,1.0,2.0,3.0,4.0
+gene_1,2.6842520940552,5.2521840950163,3.75921244478561,4.83016238235602
+gene_2,-2.29756231844181,-1.15137785595405,-0.486929672999351,-2.816582037462001
+";
+        let mut case_samples = ReaderBuilder::new().from_reader(Cursor::new(cases.as_bytes()));
+        let mut control_samples =
+            ReaderBuilder::new().from_reader(Cursor::new(controls.as_bytes()));
+        let mut writer = WriterBuilder::new().from_writer(vec![]);
+
+        process_csvs(
+            &mut case_samples,
+            &mut control_samples,
+            &mut writer,
+            &AnalysisOptions::default(),
+        );
+
+        let output_data = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+        assert_eq!(
+            output_data,
+            "\
+row_names,cohen_d
+gene_1,-2.6462737190827195
+gene_2,1.7993829062243292
+gene_3,-10.169673185162345
+"
+        )
+    }
+
+    #[test]
+    fn itegration_select_columns() {
+        // Only sample1 and sample3 are kept from the case matrix.
+        let cases = "\
+row_names,sample1,sample2,sample3
+gene_1,2.2,99.0,3.1
+";
+        let controls = "\
+row_names,sample4,sample5,sample6
+gene_1,12.6,11.1,12.3
+";
+        let mut case_samples = ReaderBuilder::new().from_reader(Cursor::new(cases.as_bytes()));
+        let mut control_samples =
+            ReaderBuilder::new().from_reader(Cursor::new(controls.as_bytes()));
+        let mut writer = WriterBuilder::new().from_writer(vec![]);
+
+        // The selection is matched against each file's own header, so it lists
+        // the wanted samples from both the case and control matrices.
+        let options = AnalysisOptions {
+            select: ["sample1", "sample3", "sample4", "sample6"]
+                .iter()
+                .map(|s| s.to_string())
+                .collect(),
+            ..Default::default()
+        };
+        process_csvs(&mut case_samples, &mut control_samples, &mut writer, &options);
+
+        let output_data = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+        assert_eq!(
+            output_data,
+            "\
+row_names,cohen_d
+gene_1,-20.660214046433417
+"
+        )
+    }
+
+    #[test]
+    fn z_score_common_levels() {
+        assert!(kinda_equal(z_score(0.95), 1.959963984540054, 1e-6));
+        assert!(kinda_equal(z_score(0.99), 2.5758293035489004, 1e-6));
+        assert!(kinda_equal(z_score(0.90), 1.6448536269514722, 1e-6));
+    }
+
+    #[test]
+    fn itegration_ci() {
+        let cases = "\
+row_names,sample1,sample2,sample3
+gene_1,2.2,1.3,3.1
+";
+        let controls = "\
+row_names,sample4,sample5,sample6
+gene_1,12.6,11.1,12.3
+";
+        let mut case_samples = ReaderBuilder::new().from_reader(Cursor::new(cases.as_bytes()));
+        let mut control_samples =
+            ReaderBuilder::new().from_reader(Cursor::new(controls.as_bytes()));
+        let mut writer = WriterBuilder::new().from_writer(vec![]);
+
+        let options = AnalysisOptions {
+            ci: true,
+            ..Default::default()
+        };
+        process_csvs(&mut case_samples, &mut control_samples, &mut writer, &options);
+
+        let output_data = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+        assert_eq!(
+            output_data,
+            "\
+row_names,cohen_d,ci_lower,ci_upper
+gene_1,-11.549410759380276,-18.277087396762433,-4.82173412199812
+"
+        )
+    }
+
+    #[test]
+    fn benjamini_hochberg_monotone() {
+        // Ascending raw p-values with m = 3: the two smallest both adjust to
+        // 0.015 (0.005 * 3 / 1 and 0.01 * 3 / 2) and the largest stays at 0.5.
+        let adjusted = benjamini_hochberg(&[0.005, 0.01, 0.5]);
+        assert!(kinda_equal(adjusted[0], 0.015, 1e-12));
+        assert!(kinda_equal(adjusted[1], 0.015, 1e-12));
+        assert!(kinda_equal(adjusted[2], 0.5, 1e-12));
+    }
+
+    #[test]
+    fn benjamini_hochberg_skips_nan() {
+        let adjusted = benjamini_hochberg(&[0.01, f64::NAN, 0.02]);
+        // m = 2, so the NaN row is ignored entirely.
+        assert!(kinda_equal(adjusted[0], 0.02, 1e-12));
+        assert!(adjusted[1].is_nan());
+        assert!(kinda_equal(adjusted[2], 0.02, 1e-12));
+    }
+
+    #[test]
+    fn itegration_permutations() {
+        let cases = "\
+row_names,sample1,sample2,sample3
+gene_1,2.2,1.3,3.1
+gene_2,1.3,2.2,3.1
+";
+        let controls = "\
+row_names,sample4,sample5,sample6
+gene_1,12.6,11.1,12.3
+gene_2,11.1,12.3,12.6
+";
+        let mut case_samples = ReaderBuilder::new().from_reader(Cursor::new(cases.as_bytes()));
+        let mut control_samples =
+            ReaderBuilder::new().from_reader(Cursor::new(controls.as_bytes()));
+        let mut writer = WriterBuilder::new().from_writer(vec![]);
+
+        let options = AnalysisOptions {
+            permutations: 20,
+            seed: 42,
+            ..Default::default()
+        };
+        process_csvs(&mut case_samples, &mut control_samples, &mut writer, &options);
+
+        // A fixed seed makes the permutation draw reproducible.
+        let output_data = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+        assert_eq!(
+            output_data,
+            "\
+row_names,cohen_d,p_value,fdr
+gene_1,-11.549410759380276,0.15,0.15
+gene_2,-11.549410759380276,0.15,0.15
+"
+        )
+    }
+
+    #[test]
+    fn gzip_detection() {
+        assert!(is_gzip(Path::new("matrix.csv.gz"), false));
+        assert!(is_gzip(Path::new("MATRIX.CSV.GZ"), false));
+        assert!(!is_gzip(Path::new("matrix.csv"), false));
+        // The flag forces it on even for a plain extension.
+        assert!(is_gzip(Path::new("matrix.csv"), true));
+    }
+
+    #[test]
+    fn itegration_na_skip() {
+        // gene_1 keeps two case values after dropping the NA, so it still has a
+        // defined effect size; gene_2 is left with a single case value and so
+        // must fall back to NaN.
+        let cases = "\
+row_names,sample1,sample2,sample3
+gene_1,2.2,NA,3.1
+gene_2,1.3,NA,NA
+";
+        let controls = "\
+row_names,sample4,sample5,sample6
+gene_1,12.6,11.1,12.3
+gene_2,11.1,12.3,12.6
+";
+        let mut case_samples = ReaderBuilder::new().from_reader(Cursor::new(cases.as_bytes()));
+        let mut control_samples =
+            ReaderBuilder::new().from_reader(Cursor::new(controls.as_bytes()));
+        let mut writer = WriterBuilder::new().from_writer(vec![]);
+
+        let options = AnalysisOptions {
+            na_values: ["NA".to_string()].into_iter().collect(),
+            ..Default::default()
+        };
+        process_csvs(&mut case_samples, &mut control_samples, &mut writer, &options);
+
+        let output_data = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+        assert_eq!(
+            output_data,
+            "\
+row_names,cohen_d
+gene_1,-12.550618232502252
+gene_2,NaN
 "
         )
     }